@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0 Copyright (c) Microsoft Corporation
 // Author: Jon Lange (jlange@microsoft.com)
 
-use crate::cpu::idt::svsm::common_isr_handler;
+use crate::cpu::idt::svsm::{common_isr_handler, raise_nmi};
 use crate::cpu::percpu::this_cpu;
 use crate::error::SvsmError;
 use crate::mm::page_visibility::{make_page_private, make_page_shared};
@@ -12,7 +12,7 @@ use bitfield_struct::bitfield;
 use core::cell::UnsafeCell;
 use core::mem::ManuallyDrop;
 use core::ops::Deref;
-use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
 
 #[bitfield(u8)]
 pub struct HVDoorbellFlags {
@@ -48,6 +48,82 @@ pub struct HVExtIntInfo {
     pub isr: [AtomicU32; 8],
 }
 
+// The low 32 vectors are reserved for x86 exceptions and are never raised
+// as external interrupts, so `irr` only covers vectors 32-255.
+const IRR_BASE_VECTOR: u8 = 32;
+
+// Returns the highest set bit across `words`, treated as a bitmap of
+// vectors starting at `base_vector`, or `None` if no bit is set.
+fn highest_set_vector(words: &[AtomicU32], base_vector: u8) -> Option<u8> {
+    for (i, word) in words.iter().enumerate().rev() {
+        let bits = word.load(Ordering::Relaxed);
+        if bits != 0 {
+            let bit = 31 - bits.leading_zeros();
+            return Some(base_vector + (i as u32 * 32 + bit) as u8);
+        }
+    }
+    None
+}
+
+fn set_vector(words: &[AtomicU32], base_vector: u8, vector: u8) {
+    let rel = (vector - base_vector) as usize;
+    words[rel / 32].fetch_or(1 << (rel % 32), Ordering::Relaxed);
+}
+
+fn clear_vector(words: &[AtomicU32], base_vector: u8, vector: u8) {
+    let rel = (vector - base_vector) as usize;
+    words[rel / 32].fetch_and(!(1 << (rel % 32)), Ordering::Relaxed);
+}
+
+impl HVExtIntInfo {
+    fn highest_pending_irr(&self) -> Option<u8> {
+        highest_set_vector(&self.irr, IRR_BASE_VECTOR)
+    }
+
+    fn highest_in_service(&self) -> Option<u8> {
+        highest_set_vector(&self.isr, 0)
+    }
+
+    fn clear_irr(&self, vector: u8) {
+        clear_vector(&self.irr, IRR_BASE_VECTOR, vector);
+    }
+
+    fn set_isr(&self, vector: u8) {
+        set_vector(&self.isr, 0, vector);
+    }
+
+    fn clear_isr(&self, vector: u8) {
+        clear_vector(&self.isr, 0, vector);
+    }
+
+    fn set_irr(&self, vector: u8) {
+        set_vector(&self.irr, IRR_BASE_VECTOR, vector);
+    }
+
+    fn status(&self) -> HVExtIntStatus {
+        HVExtIntStatus::from(self.status.load(Ordering::Relaxed))
+    }
+
+    /// Selects the highest-priority pending vector and moves it from IRR to
+    /// ISR, honoring APIC priority ordering against the vector already in
+    /// service (class = vector >> 4; only a strictly higher class preempts
+    /// the one in service). Returns the vector dispatched, if any.
+    fn acknowledge_highest_priority(&self) -> Option<u8> {
+        let vector = self.highest_pending_irr()?;
+
+        if let Some(in_service) = self.highest_in_service() {
+            if (vector >> 4) <= (in_service >> 4) {
+                return None;
+            }
+        }
+
+        self.clear_irr(vector);
+        self.set_isr(vector);
+
+        Some(vector)
+    }
+}
+
 /// An allocation containing the `#HV` doorbell page.
 #[derive(Debug)]
 pub struct HVDoorbellPage(PageBox<HVDoorbell>);
@@ -100,6 +176,37 @@ impl Drop for HVDoorbellPage {
     }
 }
 
+/// A callback invoked when the hypervisor reports an `#MC` pending on this
+/// VMPL's doorbell. Registered with [`set_mc_handler`]; without one, `#MC`
+/// remains fatal to the service module.
+pub type McHandler = fn();
+
+// Stored as the bit pattern of an `McHandler` rather than an `AtomicPtr<()>`:
+// a plain data-pointer round trip doesn't guarantee preserving a function
+// pointer's value on every target, whereas transmuting between `fn()` and
+// its same-sized integer representation is well-defined.
+static MC_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers `handler` to run in place of panicking when the doorbell
+/// reports a pending `#MC`, so a downstream consumer can attempt to report
+/// or gracefully terminate the affected guest context instead of bringing
+/// down the whole service module.
+pub fn set_mc_handler(handler: McHandler) {
+    MC_HANDLER.store(handler as usize, Ordering::Release);
+}
+
+fn handle_machine_check() {
+    let handler = MC_HANDLER.load(Ordering::Acquire);
+    if handler == 0 {
+        panic!("#MC exception delivered via #HV");
+    }
+
+    // SAFETY: the only value ever stored here was cast from a valid
+    // `McHandler` by `set_mc_handler`.
+    let handler: McHandler = unsafe { core::mem::transmute(handler) };
+    handler();
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct HVDoorbell {
@@ -107,6 +214,10 @@ pub struct HVDoorbell {
     pub flags: AtomicU8,
     pub no_eoi_required: AtomicU8,
     pub per_vmpl_events: AtomicU8,
+    // Reserved by the #HV doorbell page layout in the GHCB specification:
+    // the hypervisor is contractually defined to never read or write these
+    // bytes, so they are guest-private scratch space, not merely unused by
+    // convention. `nmi_latch` below relies on that guarantee.
     reserved_63_4: UnsafeCell<[u8; 60]>,
     pub per_vmpl: [HVExtIntInfo; 3],
 }
@@ -116,7 +227,8 @@ impl HVDoorbell {
         // Clear the NoFurtherSignal bit before processing.  If any additional
         // signal comes in after processing has commenced, it may be missed by
         // this loop, but it will be detected when interrupts are processed
-        // again.  Also clear the NMI bit, since NMIs are not expected.
+        // again.  Also clear the global NMI bit; it is dispatched below, not
+        // ignored.
         let no_further_signal_mask: u8 = HVDoorbellFlags::new()
             .with_no_further_signal(true)
             .with_nmi_pending(true)
@@ -126,10 +238,12 @@ impl HVDoorbell {
                 .fetch_and(!no_further_signal_mask, Ordering::Relaxed),
         );
 
-        // #MC handling is not possible, so panic if a machine check has
-        // occurred.
         if flags.mc_pending() {
-            panic!("#MC exception delivered via #HV");
+            handle_machine_check();
+        }
+
+        if flags.nmi_pending() {
+            self.dispatch_nmi();
         }
 
         // Consume interrupts as long as they are available.
@@ -142,8 +256,118 @@ impl HVDoorbell {
             common_isr_handler(vector as usize);
         }
 
-        // Ignore per-VMPL events; these will be consumed when APIC emulation
-        // is performed.
+        // The "no EOI required" hint covers this whole doorbell pass, not
+        // any single VMPL, so it must be read exactly once here rather than
+        // once per VMPL below: reading it again per VMPL would consume the
+        // one-shot latch on the first VMPL and force every later one to
+        // perform an explicit EOI regardless of the hypervisor's hint.
+        let skip_eoi = self.no_eoi_required();
+
+        // Service each lower VMPL's emulated local APIC: dispatch the
+        // highest-priority pending vector, then the next, until none are
+        // left with higher priority than what's already in service, a
+        // level-triggered vector has been re-latched, or the status word
+        // reports only a single pending vector.
+        for info in self.per_vmpl.iter() {
+            self.process_vmpl_nmi(info);
+            self.process_vmpl_events(info, skip_eoi);
+        }
+    }
+
+    // Reinterprets the first byte of the structurally reserved scratch
+    // space as a software-only latch. It has no hardware meaning; per the
+    // #HV doorbell page layout, the hypervisor never reads or writes this
+    // range, so no concurrent update from the hypervisor side can ever
+    // clobber it. States: 0 = idle, 1 = a dispatch owns NMI delivery,
+    // 3 (1 | 2) = owned, and a nested NMI has asked for one more round
+    // before ownership is given up.
+    fn nmi_latch(&self) -> &AtomicU8 {
+        // SAFETY: reserved_63_4 is guest-private scratch space at least one
+        // byte in size, never written by the hypervisor.
+        unsafe { &*self.reserved_63_4.get().cast::<AtomicU8>() }
+    }
+
+    // Routes a pending NMI into the IDT NMI path. If a nested NMI arrives
+    // while delivery is already underway on this CPU, it is latched rather
+    // than dropped, and the in-progress dispatch loops once more before
+    // releasing ownership.
+    fn dispatch_nmi(&self) {
+        let latch = self.nmi_latch();
+
+        match latch.fetch_or(1, Ordering::AcqRel) {
+            0 => (), // we are the new owner of delivery
+            1 => {
+                // Already owned: ask the owner for one more round.
+                latch.fetch_or(2, Ordering::AcqRel);
+                return;
+            }
+            _ => return, // a retry is already queued
+        }
+
+        loop {
+            raise_nmi();
+
+            match latch.compare_exchange(1, 0, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(_) => latch.store(1, Ordering::Release),
+            }
+        }
+    }
+
+    // Dispatches a pending per-VMPL NMI, if `info.status()` reports one.
+    // Unlike the global `nmi_pending` flag, this bit shares its atomic word
+    // with `pending_vector`/`level_sensitive`/`multiple_vectors`, so it is
+    // cleared with a CAS loop rather than a plain fetch-and, to avoid
+    // clobbering a concurrent update to those fields.
+    fn process_vmpl_nmi(&self, info: &HVExtIntInfo) {
+        let mut raw = info.status.load(Ordering::Relaxed);
+        loop {
+            let status = HVExtIntStatus::from(raw);
+            if !status.nmi_pending() {
+                return;
+            }
+
+            let cleared: u32 = status.with_nmi_pending(false).into();
+            match info.status.compare_exchange_weak(
+                raw,
+                cleared,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(new) => raw = new,
+            }
+        }
+
+        self.dispatch_nmi();
+    }
+
+    fn process_vmpl_events(&self, info: &HVExtIntInfo, skip_eoi: bool) {
+        while let Some(vector) = info.acknowledge_highest_priority() {
+            common_isr_handler(vector as usize);
+
+            if !skip_eoi {
+                info.clear_isr(vector);
+            }
+
+            // A level-triggered source keeps asserting its line until the
+            // device is serviced; re-latch it in IRR so it's reconsidered
+            // on the next doorbell pass. Without an in-service gate on
+            // this vector, re-dispatching it immediately in this same
+            // loop would spin forever, so stop here instead.
+            let status = info.status();
+            if status.level_sensitive() && status.pending_vector() == vector {
+                info.set_irr(vector);
+                break;
+            }
+
+            // If the status word, read after this vector was handled, says
+            // only one vector was pending, there's nothing left to rescan
+            // for.
+            if !status.multiple_vectors() {
+                break;
+            }
+        }
     }
 
     pub fn no_eoi_required(&self) -> bool {
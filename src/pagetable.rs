@@ -1,12 +1,18 @@
+use core::arch::asm;
 use core::ops::{Index, IndexMut};
 use core::alloc::{GlobalAlloc, Layout};
 use super::allocator::ALLOCATOR;
 use super::util::*;
 
 pub const PAGE_SIZE		: usize = 4096;
-//const PAGE_SIZE_2M	: usize = 1024 * PAGE_SIZE;
+const PAGE_SIZE_2M	: usize = 512 * PAGE_SIZE;
+const PAGE_SIZE_1G	: usize = 512 * PAGE_SIZE_2M;
 const ENTRY_COUNT	: usize = 512;
 
+// Above this many pages, a single flush_tlb() (which reloads CR3 and thereby
+// flushes the whole non-global TLB) is cheaper than one INVLPG per page.
+const TLB_FLUSH_RANGE_PAGES	: usize = 32;
+
 pub type PhysAddr	= usize;
 pub type VirtAddr	= usize;
 
@@ -28,6 +34,30 @@ pub fn flush_tlb_global() {
 	write_cr4(cr4);
 }
 
+pub fn flush_tlb_address(vaddr : VirtAddr) {
+	unsafe {
+		asm!("invlpg [{}]", in(reg) vaddr, options(nostack, preserves_flags));
+	}
+}
+
+// Invalidates `size` bytes starting at `vaddr_start` with one INVLPG per 4K
+// page, falling back to a full flush_tlb() once that would touch more than
+// TLB_FLUSH_RANGE_PAGES pages, where a CR3 reload ends up cheaper.
+fn flush_tlb_range(vaddr_start : VirtAddr, size : usize) {
+	if size > TLB_FLUSH_RANGE_PAGES * PAGE_SIZE {
+		flush_tlb();
+		return;
+	}
+
+	let mut vaddr = vaddr_start;
+	let end = vaddr_start + size;
+
+	while vaddr < end {
+		flush_tlb_address(vaddr);
+		vaddr += PAGE_SIZE;
+	}
+}
+
 fn encrypt_mask() -> usize {
 	1 << 51
 	//0
@@ -100,6 +130,12 @@ impl IndexMut<usize> for PTPage {
 	}
 }
 
+impl PTPage {
+	fn is_empty(&self) -> bool {
+		self.entries.iter().all(|entry| entry.is_clear())
+	}
+}
+
 pub enum Mapping<'a> {
 	Level3(&'a mut PTEntry),
 	Level2(&'a mut PTEntry),
@@ -107,6 +143,45 @@ pub enum Mapping<'a> {
 	Level0(&'a mut PTEntry),
 }
 
+// Walks a contiguous virtual range one 4K page at a time, caching the
+// L2/L1/L0 table pointers it has already descended into so that consecutive
+// pages sharing those tables don't re-walk from the root. Each cached table
+// is only re-descended when the index that selects it changes, i.e. when the
+// cursor crosses into a different L3, L2 or L1 entry.
+struct MappingCursor {
+	vaddr: VirtAddr,
+	paddr: PhysAddr,
+	remaining: usize,
+	idx3: usize,
+	idx2: usize,
+	idx1: usize,
+	l2: *mut PTPage,
+	l1: *mut PTPage,
+	l0: *mut PTPage,
+}
+
+impl MappingCursor {
+	fn new(vaddr : VirtAddr, paddr : PhysAddr, size : usize) -> Self {
+		MappingCursor {
+			vaddr,
+			paddr,
+			remaining: size,
+			idx3: ENTRY_COUNT,
+			idx2: ENTRY_COUNT,
+			idx1: ENTRY_COUNT,
+			l2: core::ptr::null_mut(),
+			l1: core::ptr::null_mut(),
+			l0: core::ptr::null_mut(),
+		}
+	}
+
+	fn advance(&mut self) {
+		self.vaddr += PAGE_SIZE;
+		self.paddr += PAGE_SIZE;
+		self.remaining -= PAGE_SIZE;
+	}
+}
+
 #[repr(C)]
 pub struct PageTable {
 	root: PTPage,
@@ -139,6 +214,34 @@ impl PageTable {
 
 	}
 
+	// Returns the page table pointed to by `entry`, allocating and installing
+	// a freshly zeroed one if `entry` is not yet present. Fails if `entry` is
+	// already present as a huge leaf, since that can't be descended into.
+	fn ensure_page_table(entry : &mut PTEntry) -> Result<&'static mut PTPage, ()> {
+		let flags = entry.flags();
+
+		if flags.contains(PTEntryFlags::PRESENT) {
+			return PageTable::entry_to_pagetable(*entry).ok_or(());
+		}
+
+		let page = PageTable::allocate_page_table();
+
+		if page.is_null() {
+			return Err(());
+		}
+
+		unsafe {
+			for i in 0..ENTRY_COUNT {
+				(*page).entries[i].clear();
+			}
+		}
+
+		let addr = page as PhysAddr;
+		entry.set(set_c_bit(addr), PTEntryFlags::PRESENT | PTEntryFlags::WRITABLE);
+
+		Ok(unsafe { &mut *page })
+	}
+
 	fn walk_addr_lvl0<'a>(page: &'a mut PTPage, vaddr : VirtAddr) -> Mapping<'a> {
 		let idx = PageTable::index::<0>(vaddr);
 
@@ -182,7 +285,7 @@ impl PageTable {
 		PageTable::walk_addr_lvl3(&mut self.root, vaddr)
 	}
 
-	fn do_split_4k(entry : &mut PTEntry) -> Result<(), ()> {
+	fn do_split_4k(entry : &mut PTEntry, vaddr : VirtAddr) -> Result<(), ()> {
 		let page = PageTable::allocate_page_table();
 		let mut flags = entry.flags();
 
@@ -208,20 +311,148 @@ impl PageTable {
 		let addr_2m = page as PhysAddr;
 		entry.set(set_c_bit(addr_2m), flags);
 
-		flush_tlb();
+		// The huge entry being replaced was cached as a single large-page
+		// TLB entry; INVLPG on any address it covers invalidates that whole
+		// entry, so there's no need for a full flush_tlb() here.
+		flush_tlb_address(vaddr);
 
 		Ok(())
 	}
 
-	pub fn split_4k(mapping : Mapping) -> Result<(),()> {
+	pub fn split_4k(mapping : Mapping, vaddr : VirtAddr) -> Result<(),()> {
 		match mapping {
 			Mapping::Level0(_entry) => Ok(()),
-			Mapping::Level1( entry) => PageTable::do_split_4k(entry),
+			Mapping::Level1( entry) => PageTable::do_split_4k(entry, vaddr),
 			Mapping::Level2(_entry) => Err(()),
 			Mapping::Level3(_entry) => Err(()),
 		}
 	}
 
+	/// Maps a single 2M page at `vaddr`, allocating any missing L2/L1 tables
+	/// along the way. `vaddr` and `paddr` must both be 2M-aligned.
+	pub fn map_2m(&mut self, vaddr : VirtAddr, paddr : PhysAddr, flags : PTEntryFlags) -> Result<(), ()> {
+		assert!(vaddr % PAGE_SIZE_2M == 0);
+		assert!(paddr % PAGE_SIZE_2M == 0);
+
+		let idx3 = PageTable::index::<3>(vaddr);
+		let idx2 = PageTable::index::<2>(vaddr);
+		let idx1 = PageTable::index::<1>(vaddr);
+
+		let l2 = PageTable::ensure_page_table(&mut self.root[idx3])?;
+		let l1 = PageTable::ensure_page_table(&mut l2[idx2])?;
+
+		let target = l1[idx1].flags();
+		if target.contains(PTEntryFlags::PRESENT) && !target.contains(PTEntryFlags::HUGE) {
+			// A present, non-huge entry here points at a whole sub-tree of
+			// L0 tables; overwriting it with a HUGE leaf would leak them.
+			return Err(());
+		}
+
+		l1[idx1].set(set_c_bit(paddr), flags | PTEntryFlags::PRESENT | PTEntryFlags::HUGE);
+
+		flush_tlb();
+
+		Ok(())
+	}
+
+	/// Maps a single 1G page at `vaddr`, allocating the missing L2 table
+	/// along the way. `vaddr` and `paddr` must both be 1G-aligned.
+	pub fn map_1g(&mut self, vaddr : VirtAddr, paddr : PhysAddr, flags : PTEntryFlags) -> Result<(), ()> {
+		assert!(vaddr % PAGE_SIZE_1G == 0);
+		assert!(paddr % PAGE_SIZE_1G == 0);
+
+		let idx3 = PageTable::index::<3>(vaddr);
+		let idx2 = PageTable::index::<2>(vaddr);
+
+		let l2 = PageTable::ensure_page_table(&mut self.root[idx3])?;
+
+		let target = l2[idx2].flags();
+		if target.contains(PTEntryFlags::PRESENT) && !target.contains(PTEntryFlags::HUGE) {
+			// A present, non-huge entry here points at a whole sub-tree of
+			// L1/L0 tables; overwriting it with a HUGE leaf would leak them.
+			return Err(());
+		}
+
+		l2[idx2].set(set_c_bit(paddr), flags | PTEntryFlags::PRESENT | PTEntryFlags::HUGE);
+
+		flush_tlb();
+
+		Ok(())
+	}
+
+	// Returns a page table page to ALLOCATOR. The caller must have already
+	// cleared or replaced the entry pointing at it and flushed the TLB
+	// entries covering it.
+	fn free_page_table(page : &mut PTPage) {
+		let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+
+		unsafe {
+			ALLOCATOR.dealloc(page as *mut PTPage as *mut u8, layout);
+		}
+	}
+
+	/// Inspects the 4K leaf table backing the 2M region containing `vaddr`
+	/// and, if all 512 entries are present, physically contiguous, agree on
+	/// the C-bit, and share identical permission flags, replaces the L1
+	/// entry with a single `HUGE` 2M entry and frees the leaf table. Fails
+	/// without changing anything if the table cannot be coalesced.
+	pub fn try_coalesce_2m(&mut self, vaddr : VirtAddr) -> Result<(), ()> {
+		let vaddr = vaddr & !(PAGE_SIZE_2M - 1);
+
+		let idx3 = PageTable::index::<3>(vaddr);
+		let idx2 = PageTable::index::<2>(vaddr);
+		let idx1 = PageTable::index::<1>(vaddr);
+
+		let l2 = PageTable::entry_to_pagetable(self.root[idx3]).ok_or(())?;
+		let l1 = PageTable::entry_to_pagetable(l2[idx2]).ok_or(())?;
+		let l0 = PageTable::entry_to_pagetable(l1[idx1]).ok_or(())?;
+
+		let first = l0[0];
+		let base_flags = first.flags();
+
+		if !base_flags.contains(PTEntryFlags::PRESENT) {
+			return Err(());
+		}
+
+		let base_addr = first.address();
+
+		if base_addr % PAGE_SIZE_2M != 0 {
+			return Err(());
+		}
+
+		let base_encrypted = first.0 & encrypt_mask() as u64 != 0;
+
+		// ACCESSED/DIRTY are hardware-managed and may legitimately differ
+		// across otherwise-identical pages; HUGE never applies to an L0
+		// leaf. None of them should factor into the permission comparison.
+		let ignored_flags = PTEntryFlags::ACCESSED | PTEntryFlags::DIRTY | PTEntryFlags::HUGE;
+		let base_perm = base_flags & !ignored_flags;
+
+		for i in 0..ENTRY_COUNT {
+			let entry = l0[i];
+			let flags = entry.flags() & !ignored_flags;
+			let encrypted = entry.0 & encrypt_mask() as u64 != 0;
+
+			if flags != base_perm || encrypted != base_encrypted {
+				return Err(());
+			}
+
+			if entry.address() != base_addr + i * PAGE_SIZE {
+				return Err(());
+			}
+		}
+
+		let addr = if base_encrypted { set_c_bit(base_addr) } else { base_addr };
+
+		l1[idx1].set(addr, base_perm | PTEntryFlags::HUGE);
+
+		flush_tlb();
+
+		PageTable::free_page_table(l0);
+
+		Ok(())
+	}
+
 	fn clear_c_bit(entry : &mut PTEntry) {
 		let flags = entry.flags();
 		let addr  = entry.address();
@@ -241,30 +472,266 @@ impl PageTable {
 	pub fn set_shared_4k(&mut self, vaddr : VirtAddr) -> Result<(), ()> {
 		let mapping = self.walk_addr(vaddr);
 
-		if let Err(_e) = PageTable::split_4k(mapping) {
+		if let Err(_e) = PageTable::split_4k(mapping, vaddr) {
 			return Err(());
 		}
 
 		if let Mapping::Level0(entry) = self.walk_addr(vaddr) {
 			PageTable::clear_c_bit(entry);
+			flush_tlb_address(vaddr);
 			Ok(())
 		} else {
 			Err(())
 		}
 	}
-	
+
 	pub fn set_encrypted_4k(&mut self, vaddr : VirtAddr) -> Result<(), ()> {
 		let mapping = self.walk_addr(vaddr);
 
-		if let Err(_e) = PageTable::split_4k(mapping) {
+		if let Err(_e) = PageTable::split_4k(mapping, vaddr) {
 			return Err(());
 		}
 
 		if let Mapping::Level0(entry) = self.walk_addr(vaddr) {
 			PageTable::set_c_bit(entry);
+			flush_tlb_address(vaddr);
 			Ok(())
 		} else {
 			Err(())
 		}
 	}
+
+	// Applies `set`/`clear` to the 4K leaf entry at `vaddr`, splitting an
+	// overlapping 2M HUGE entry first if necessary, and preserving the
+	// entry's address and C-bit. Fails if `vaddr` is not currently mapped.
+	fn set_flags_4k(&mut self, vaddr : VirtAddr, set : PTEntryFlags, clear : PTEntryFlags) -> Result<(), ()> {
+		let mapping = self.walk_addr(vaddr);
+
+		// A non-present L1 entry is not a huge page to split.
+		if let Mapping::Level1(ref entry) = mapping {
+			if !entry.flags().contains(PTEntryFlags::PRESENT) {
+				return Err(());
+			}
+		}
+
+		if let Err(_e) = PageTable::split_4k(mapping, vaddr) {
+			return Err(());
+		}
+
+		if let Mapping::Level0(entry) = self.walk_addr(vaddr) {
+			let mut flags = entry.flags();
+
+			if !flags.contains(PTEntryFlags::PRESENT) {
+				return Err(());
+			}
+
+			let encrypted = entry.0 & encrypt_mask() as u64 != 0;
+			let addr = if encrypted { set_c_bit(entry.address()) } else { entry.address() };
+
+			flags.insert(set);
+			flags.remove(clear);
+
+			entry.set(addr, flags);
+
+			Ok(())
+		} else {
+			Err(())
+		}
+	}
+
+	// Descends to the L0 (4K leaf) entry for `cursor.vaddr`, allocating any
+	// missing intermediate tables. Re-descends only the levels whose index
+	// has rolled over since the cursor's last step.
+	fn cursor_leaf_entry<'a>(&'a mut self, cursor : &mut MappingCursor) -> Result<&'a mut PTEntry, ()> {
+		let idx3 = PageTable::index::<3>(cursor.vaddr);
+		let idx2 = PageTable::index::<2>(cursor.vaddr);
+		let idx1 = PageTable::index::<1>(cursor.vaddr);
+		let idx0 = PageTable::index::<0>(cursor.vaddr);
+
+		if idx3 != cursor.idx3 || cursor.l2.is_null() {
+			let l2 = PageTable::ensure_page_table(&mut self.root[idx3])?;
+			cursor.l2 = l2 as *mut PTPage;
+			cursor.idx3 = idx3;
+			cursor.idx2 = ENTRY_COUNT;
+		}
+
+		if idx2 != cursor.idx2 || cursor.l1.is_null() {
+			let l2_page = unsafe { &mut *cursor.l2 };
+			let l1 = PageTable::ensure_page_table(&mut l2_page[idx2])?;
+			cursor.l1 = l1 as *mut PTPage;
+			cursor.idx2 = idx2;
+			cursor.idx1 = ENTRY_COUNT;
+		}
+
+		if idx1 != cursor.idx1 || cursor.l0.is_null() {
+			let l1_page = unsafe { &mut *cursor.l1 };
+			let l0 = PageTable::ensure_page_table(&mut l1_page[idx1])?;
+			cursor.l0 = l0 as *mut PTPage;
+			cursor.idx1 = idx1;
+		}
+
+		let l0_page = unsafe { &mut *cursor.l0 };
+		Ok(&mut l0_page[idx0])
+	}
+
+	/// Maps `size` bytes (a multiple of `PAGE_SIZE`) of physically contiguous
+	/// memory starting at `paddr_start` into the virtual range starting at
+	/// `vaddr_start`, allocating any intermediate page tables that are
+	/// missing along the way.
+	pub fn map_region(&mut self, vaddr_start : VirtAddr, size : usize, paddr_start : PhysAddr, flags : PTEntryFlags) -> Result<(), ()> {
+		assert!(size % PAGE_SIZE == 0);
+
+		let mut cursor = MappingCursor::new(vaddr_start, paddr_start, size);
+
+		while cursor.remaining > 0 {
+			let entry = self.cursor_leaf_entry(&mut cursor)?;
+			entry.set(set_c_bit(cursor.paddr), flags | PTEntryFlags::PRESENT);
+			cursor.advance();
+		}
+
+		flush_tlb_range(vaddr_start, size);
+
+		Ok(())
+	}
+
+	/// Clears the 4K leaf entries covering `size` bytes starting at
+	/// `vaddr_start`. Any L0/L1/L2 table left with no present entries as a
+	/// result is returned to `ALLOCATOR` and its parent entry cleared in
+	/// turn, recursing towards the root.
+	pub fn unmap_region(&mut self, vaddr_start : VirtAddr, size : usize) -> Result<(), ()> {
+		assert!(size % PAGE_SIZE == 0);
+
+		// Above the INVLPG threshold, a full flush is done whenever a leaf
+		// table has just become empty (before it and its ancestors are
+		// reclaimed) and once more at the end, so no freed table can be
+		// reached by a speculative walk and no cleared entry stays cached.
+		// Below the threshold it's cheaper to flush just the page being
+		// unmapped before reclaiming its tables.
+		let batch_flush = size > TLB_FLUSH_RANGE_PAGES * PAGE_SIZE;
+
+		let mut cursor = MappingCursor::new(vaddr_start, 0, size);
+
+		while cursor.remaining > 0 {
+			let entry = self.cursor_leaf_entry(&mut cursor)?;
+			entry.clear();
+
+			if !batch_flush {
+				flush_tlb_address(cursor.vaddr);
+			} else if unsafe { (*cursor.l0).is_empty() } {
+				flush_tlb();
+			}
+
+			self.reclaim_ancestors(&mut cursor);
+
+			cursor.advance();
+		}
+
+		if batch_flush {
+			flush_tlb();
+		}
+
+		Ok(())
+	}
+
+	// Frees the L0 table holding the entry just cleared at `cursor`, and its
+	// L1/L2 ancestors in turn, as long as each becomes fully empty. Stops at
+	// the first table up the chain that still has live entries. The caller
+	// must already have flushed the TLB for the address(es) covered by the
+	// table being freed.
+	//
+	// Whenever a table is freed, the cursor's cached pointer/index for it is
+	// invalidated so a later cursor_leaf_entry() call re-descends instead of
+	// dereferencing the table that was just handed back to ALLOCATOR.
+	fn reclaim_ancestors(&mut self, cursor : &mut MappingCursor) {
+		let l1_page = unsafe { &mut *cursor.l1 };
+		if !PageTable::reclaim_if_empty(&mut l1_page[cursor.idx1]) {
+			return;
+		}
+
+		cursor.l0 = core::ptr::null_mut();
+		cursor.idx1 = ENTRY_COUNT;
+
+		let l2_page = unsafe { &mut *cursor.l2 };
+		if !PageTable::reclaim_if_empty(&mut l2_page[cursor.idx2]) {
+			return;
+		}
+
+		cursor.l1 = core::ptr::null_mut();
+		cursor.idx2 = ENTRY_COUNT;
+
+		if !PageTable::reclaim_if_empty(&mut self.root[cursor.idx3]) {
+			return;
+		}
+
+		cursor.l2 = core::ptr::null_mut();
+		cursor.idx3 = ENTRY_COUNT;
+	}
+
+	// Frees the page table pointed to by `entry` if it has no present
+	// entries left, clearing `entry` itself. Returns whether it was freed.
+	fn reclaim_if_empty(entry : &mut PTEntry) -> bool {
+		let flags = entry.flags();
+
+		if !flags.contains(PTEntryFlags::PRESENT) || flags.contains(PTEntryFlags::HUGE) {
+			return false;
+		}
+
+		let table = match PageTable::entry_to_pagetable(*entry) {
+			Some(table) => table,
+			None => return false,
+		};
+
+		if !table.is_empty() {
+			return false;
+		}
+
+		PageTable::free_page_table(table);
+		entry.clear();
+
+		true
+	}
+
+	/// Applies `set`/`clear` to the permission flags of every 4K leaf entry
+	/// covering `size` bytes starting at `vaddr_start`, preserving each
+	/// entry's address and C-bit. `vaddr_start` and `size` must already be
+	/// mapped; any overlapping 2M `HUGE` entry is split first.
+	pub fn set_flags_region(&mut self, vaddr_start : VirtAddr, size : usize, set : PTEntryFlags, clear : PTEntryFlags) -> Result<(), ()> {
+		assert!(size % PAGE_SIZE == 0);
+
+		let mut vaddr = vaddr_start;
+		let end = vaddr_start + size;
+
+		while vaddr < end {
+			if let Err(e) = self.set_flags_4k(vaddr, set, clear) {
+				// Pages up to (but not including) `vaddr` were already
+				// updated; flush them so a caller treating `Err` as
+				// "nothing changed" isn't also left with a stale TLB for
+				// the entries that did change.
+				if vaddr > vaddr_start {
+					flush_tlb_range(vaddr_start, vaddr - vaddr_start);
+				}
+				return Err(e);
+			}
+			vaddr += PAGE_SIZE;
+		}
+
+		flush_tlb_range(vaddr_start, size);
+
+		Ok(())
+	}
+
+	/// Marks `size` bytes starting at `vaddr_start` non-executable.
+	pub fn make_nx(&mut self, vaddr_start : VirtAddr, size : usize) -> Result<(), ()> {
+		self.set_flags_region(vaddr_start, size, PTEntryFlags::NX, PTEntryFlags::empty())
+	}
+
+	/// Marks `size` bytes starting at `vaddr_start` read-only.
+	pub fn make_read_only(&mut self, vaddr_start : VirtAddr, size : usize) -> Result<(), ()> {
+		self.set_flags_region(vaddr_start, size, PTEntryFlags::empty(), PTEntryFlags::WRITABLE)
+	}
+
+	/// Marks `size` bytes starting at `vaddr_start` writable.
+	pub fn make_writable(&mut self, vaddr_start : VirtAddr, size : usize) -> Result<(), ()> {
+		self.set_flags_region(vaddr_start, size, PTEntryFlags::WRITABLE, PTEntryFlags::empty())
+	}
 }